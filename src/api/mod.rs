@@ -0,0 +1,8 @@
+//!
+//! # The daemon-facing API
+//!
+//! `server` hosts the long-running snapshot loop that a blockchain
+//! process drives by feeding it block heights.
+//!
+
+pub(crate) mod server;
@@ -0,0 +1,93 @@
+//!
+//! # The snapshot daemon
+//!
+//! `run_daemon` spawns a background worker and hands the caller a
+//! `Sender<u64>`; the blockchain process pushes the latest committed
+//! block height after every block, and the worker decides when to take
+//! and when to expire snapshots according to `BtmCfg`.
+//!
+
+use crate::{abort_token, Abort, BtmCfg};
+use ruc::*;
+use std::{
+    sync::atomic::Ordering,
+    sync::mpsc::{channel, Sender},
+    thread,
+};
+
+/// start the background snapshot worker
+///
+/// Returns the channel the caller feeds block heights into; dropping it
+/// lets the worker finish and exit.
+///
+/// When `strict_snapshot` is set a failed snapshot is fatal: the worker
+/// prints the error and exits the process with a non-zero status rather
+/// than logging and moving on. Other (non-snapshot) failures request a
+/// graceful shutdown instead — the current block is allowed to finish
+/// before the worker stops.
+#[inline(always)]
+pub fn run_daemon(cfg: BtmCfg) -> Result<Sender<u64>> {
+    run_daemon_cancellable(cfg, abort_token()).c(d!())
+}
+
+/// like [`run_daemon`] but driven by a shared `abort` flag: setting it
+/// asks the worker to stop gracefully once the current block is done.
+///
+/// Transfer progress of an export is surfaced through the `report` hook
+/// on [`export_cancellable`](crate::BtmCfg::export_cancellable), which
+/// this worker does not call;
+/// [`restore_cancellable`](crate::BtmCfg::restore_cancellable) reports no
+/// progress. The snapshot worker loop itself has no streaming transfer to
+/// report.
+pub fn run_daemon_cancellable(cfg: BtmCfg, abort: Abort) -> Result<Sender<u64>> {
+    let (sender, receiver) = channel::<u64>();
+
+    thread::Builder::new()
+        .name("btm-snapshot".to_owned())
+        .spawn(move || {
+            // `itv == 0` would panic the worker on the `%` below; a zero
+            // interval has no sane meaning, so treat every block as a
+            // snapshot point instead of crashing.
+            let itv = alt!(0 == cfg.itv, 1, cfg.itv);
+
+            for height in receiver.iter() {
+                if abort.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                if 0 != height % itv {
+                    continue;
+                }
+
+                if let Err(e) = cfg.snapshot(height) {
+                    if cfg.strict_snapshot {
+                        e.print(None);
+                        std::process::exit(1);
+                    }
+                    e.print(None);
+                }
+
+                // A failure past this point is not a missing snapshot, so
+                // it does not warrant `strict_snapshot`'s hard exit; stop
+                // gracefully after the current block instead.
+                match cfg.get_sorted_snapshots() {
+                    Ok(list) if list.len() as u64 > cfg.cap => {
+                        if let Err(e) = cfg.clean_snapshots() {
+                            e.print(None);
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    // nothing is listed while snapshots are disabled
+                    Err(_) if !cfg.enable => {}
+                    Err(e) => {
+                        e.print(None);
+                        break;
+                    }
+                }
+            }
+        })
+        .c(d!())?;
+
+    Ok(sender)
+}
@@ -0,0 +1,513 @@
+//!
+//! # Portable snapshot archives
+//!
+//! A COW snapshot only protects against logical corruption on the same
+//! pool. To ship a recovery point off-host we serialize a snapshot into
+//! a single compressed stream (`zfs send` / `btrfs send` piped through a
+//! selectable compressor) and drop a sidecar checksum next to it so the
+//! archive can be verified before it is trusted for catchup.
+//!
+//! A full `send` per height is wasteful for large volumes, so exports
+//! are split into periodic *full* archives and cheaper *incremental*
+//! archives that carry only the delta from their base. Retention is
+//! tracked independently for the two kinds, and an incremental is only
+//! kept together with the full it chains back to.
+//!
+
+use crate::driver::progress::{self, Abort, Progress, Reporter};
+use ruc::{cmd, *};
+use std::{
+    collections::HashSet, fmt, fs, path::PathBuf, process::Command, str::FromStr,
+    thread, time::Duration,
+};
+
+/// how often the in-flight send is polled for completion, abort and size
+const POLL_ITV: Duration = Duration::from_millis(500);
+
+/// The compressor an archive is wrapped in.
+///
+/// Mirrors the archive-format choice exposed by Solana's
+/// `snapshot_utils`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    /// `gzip`, widest availability
+    Gzip,
+    /// `bzip2`, smaller but slower
+    Bzip2,
+    /// `zstd`, the best speed/ratio tradeoff
+    Zstd,
+}
+
+impl ArchiveFormat {
+    /// file-name suffix of an archive compressed in this format
+    #[inline(always)]
+    pub(crate) fn ext(self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Bzip2 => "bz2",
+            Self::Zstd => "zst",
+        }
+    }
+
+    /// shell fragment that compresses stdin to stdout
+    #[inline(always)]
+    fn compressor(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Bzip2 => "bzip2",
+            Self::Zstd => "zstd -q",
+        }
+    }
+
+    /// shell fragment that decompresses stdin to stdout
+    #[inline(always)]
+    fn decompressor(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip -dc",
+            Self::Bzip2 => "bzip2 -dc",
+            Self::Zstd => "zstd -dc",
+        }
+    }
+
+    /// guess the format from an archive file name
+    #[inline(always)]
+    fn from_name(name: &str) -> Result<Self> {
+        if name.ends_with(".gz") {
+            Ok(Self::Gzip)
+        } else if name.ends_with(".bz2") {
+            Ok(Self::Bzip2)
+        } else if name.ends_with(".zst") {
+            Ok(Self::Zstd)
+        } else {
+            Err(eg!("unknown archive format: {}", name))
+        }
+    }
+}
+
+impl Default for ArchiveFormat {
+    fn default() -> Self {
+        Self::Zstd
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let contents = match self {
+            Self::Gzip => "gzip",
+            Self::Bzip2 => "bzip2",
+            Self::Zstd => "zstd",
+        };
+        write!(f, "{}", contents)
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gzip" | "gz" => Ok(Self::Gzip),
+            "bzip2" | "bz2" => Ok(Self::Bzip2),
+            "zstd" | "zst" => Ok(Self::Zstd),
+            _ => Err(format!("unknown archive format: {}", s)),
+        }
+    }
+}
+
+/// A single archive on disk.
+///
+/// Names encode everything needed to rebuild the chain without re-reading
+/// the streams:
+/// - full:        `<height>-full-<sha256>.<ext>`
+/// - incremental: `<height>-incr<base>-<sha256>.<ext>`
+#[derive(Clone, Debug)]
+pub struct ArchiveEntry {
+    /// snapshot height captured by this archive
+    pub height: u64,
+    /// the base height an incremental chains back to; `None` for a full
+    pub base: Option<u64>,
+    fmt: ArchiveFormat,
+    path: PathBuf,
+}
+
+impl ArchiveEntry {
+    /// whether this archive is a self-contained full send (a chain base)
+    #[inline(always)]
+    pub fn is_full(&self) -> bool {
+        self.base.is_none()
+    }
+
+    /// on-disk path of this archive
+    #[inline(always)]
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// parse an archive file name, ignoring anything that doesn't match
+    fn parse(dir: &str, name: &str) -> Option<Self> {
+        let fmt = ArchiveFormat::from_name(name).ok()?;
+        let stem = name.rsplit_once('.').map(|(s, _)| s)?;
+        let (height, rest) = stem.split_once('-')?;
+        let height = height.parse::<u64>().ok()?;
+        let base = if let Some(rest) = rest.strip_prefix("full-") {
+            let _ = rest; // hash, unused here
+            None
+        } else if let Some(rest) = rest.strip_prefix("incr") {
+            let base = rest.split_once('-').map(|(b, _)| b)?;
+            Some(base.parse::<u64>().ok()?)
+        } else {
+            return None;
+        };
+        Some(ArchiveEntry {
+            height,
+            base,
+            fmt,
+            path: PathBuf::from(format!("{}/{}", dir, name)),
+        })
+    }
+}
+
+/// serialize `send_cmd` into an archive under `out_dir`, writing a
+/// `.sha256` sidecar; `base` is `None` for a full send, otherwise the
+/// base height of an incremental send. Returns the archive path.
+///
+/// The send runs as a child process that is polled every [`POLL_ITV`], so
+/// `abort` can kill a multi-minute transfer in flight (cleaning up the
+/// partial) and `report` streams the growing partial's size, not just a
+/// 0%/100% pair.
+pub(crate) fn export(
+    send_cmd: &str,
+    total: u64,
+    idx: u64,
+    base: Option<u64>,
+    fmt: ArchiveFormat,
+    out_dir: &str,
+    abort: &Abort,
+    report: &Reporter,
+) -> Result<PathBuf> {
+    progress::check(abort).c(d!())?;
+    report(Progress { sent: 0, total });
+
+    let tmp = format!("{}/.{}.{}.partial", out_dir, idx, fmt.ext());
+
+    // stream the snapshot through the compressor to a partial file as a
+    // child we can poll, so `abort` kills the pipe instead of blocking
+    // until a minutes-long send finishes. `pipefail` is essential: a
+    // POSIX pipeline's status is the last stage's, so without it a failed
+    // `send` feeding a happy compressor would finalize a truncated
+    // archive whose sidecar `verify()`s clean.
+    let pipe = format!(
+        "set -o pipefail; {} | {} > {}",
+        send_cmd,
+        fmt.compressor(),
+        &tmp
+    );
+    let mut child = match Command::new("bash").arg("-c").arg(&pipe).spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            info_omit!(cmd::exec_output(&format!("rm -f {}", &tmp)));
+            return Err(eg!(e));
+        }
+    };
+
+    loop {
+        match child.try_wait().c(d!())? {
+            Some(status) => {
+                if !status.success() {
+                    info_omit!(cmd::exec_output(&format!("rm -f {}", &tmp)));
+                    return Err(eg!("send pipeline failed: {}", status));
+                }
+                break;
+            }
+            None => {
+                if progress::check(abort).is_err() {
+                    info_omit!(child.kill());
+                    info_omit!(child.wait());
+                    // don't leave a half-written partial for `purge`
+                    info_omit!(cmd::exec_output(&format!("rm -f {}", &tmp)));
+                    return Err(eg!("operation aborted"));
+                }
+                // stream progress from the partial's current size
+                let sent = fs::metadata(&tmp).map(|m| m.len()).unwrap_or(0);
+                report(Progress { sent, total });
+                thread::sleep(POLL_ITV);
+            }
+        }
+    }
+
+    report(Progress { sent: total, total });
+
+    let hash = sha256(&tmp).c(d!())?;
+    let kind = match base {
+        None => "full".to_owned(),
+        Some(b) => format!("incr{}", b),
+    };
+    let archive = format!("{}/{}-{}-{}.{}", out_dir, idx, kind, hash, fmt.ext());
+
+    let finalize = format!(
+        "mv {tmp} {arch} && echo '{hash}  {arch}' > {arch}.sha256",
+        tmp = &tmp,
+        arch = &archive,
+        hash = &hash
+    );
+    cmd::exec_output(&finalize).c(d!())?;
+
+    Ok(PathBuf::from(archive))
+}
+
+/// replay a single archive entry through `recv_cmd`, verifying it first
+pub(crate) fn import(recv_cmd: &str, entry: &ArchiveEntry, abort: &Abort) -> Result<()> {
+    let path = entry.path.to_str().c(d!("non-utf8 archive path"))?;
+    progress::check(abort).c(d!())?;
+    verify(path).c(d!())?;
+    progress::check(abort).c(d!())?;
+    let pipe = format!(
+        "{} < {} | {}",
+        entry.fmt.decompressor(),
+        path,
+        recv_cmd
+    );
+    cmd::exec_output(&pipe).c(d!()).map(|_| ())
+}
+
+/// list the archives under `out_dir`, in 'DESC' order of height
+pub(crate) fn sorted_archives(out_dir: &str) -> Result<Vec<ArchiveEntry>> {
+    // a fresh target dir has no archives yet; create it so the very first
+    // export doesn't trip over `ls` before it has written anything
+    cmd::exec_output(&format!("mkdir -p {}", out_dir)).c(d!())?;
+    let output = cmd::exec_output(&format!("ls -1 {}", out_dir)).c(d!())?;
+    let mut list = output
+        .lines()
+        .filter_map(|name| ArchiveEntry::parse(out_dir, name.trim()))
+        .collect::<Vec<_>>();
+    list.sort_unstable_by(|a, b| b.height.cmp(&a.height));
+    Ok(list)
+}
+
+/// resolve the full+incremental chain that reconstructs `idx`,
+/// returned in apply order (the full base first)
+pub(crate) fn resolve_chain(
+    archives: &[ArchiveEntry],
+    idx: u64,
+) -> Result<Vec<ArchiveEntry>> {
+    let full = archives
+        .iter()
+        .filter(|a| a.is_full() && a.height <= idx)
+        .max_by_key(|a| a.height)
+        .c(d!("no full archive at or before height {}", idx))?;
+
+    let mut chain = vec![full.clone()];
+    let mut cur = full.height;
+    while cur < idx {
+        let next = archives
+            .iter()
+            .find(|a| a.base == Some(cur) && a.height <= idx)
+            .c(d!("incremental chain broken after height {}", cur))?;
+        cur = next.height;
+        chain.push(next.clone());
+    }
+
+    if cur != idx {
+        return Err(eg!("height {} is not reachable from any archive", idx));
+    }
+
+    Ok(chain)
+}
+
+/// purge archives down to `cap_full` fulls and `cap_incr` incrementals,
+/// keeping the most recent of each kind but never keeping an
+/// incremental whose base full would be gone, nor dropping a full that a
+/// kept incremental still depends on
+///
+/// Incrementals are kept by whole chains, not by individual tips: a chain
+/// can be up to `full_itv` long, so keeping only the newest tips would
+/// leave a tip that chains *through* a mid-chain incremental we just
+/// evicted, breaking `resolve_chain`. `cap_incr` therefore bounds the
+/// number of retained chain *tips*; each kept tip drags its whole base
+/// chain back to the full along with it.
+pub(crate) fn purge(out_dir: &str, cap_full: usize, cap_incr: usize) -> Result<()> {
+    let all = sorted_archives(out_dir).c(d!())?;
+    let keep = retention(&all, cap_full, cap_incr);
+    for e in all.iter() {
+        if !keep.contains(&e.height) {
+            remove(e);
+        }
+    }
+    Ok(())
+}
+
+/// decide which archive heights survive a `purge`: the newest `cap_full`
+/// fulls, plus every incremental reachable from the newest `cap_incr`
+/// chain tips whose root full is itself kept.
+///
+/// Pure (no IO) so the whole-chain retention invariant can be unit
+/// tested directly against a synthetic archive set.
+fn retention(all: &[ArchiveEntry], cap_full: usize, cap_incr: usize) -> HashSet<u64> {
+    let mut keep = all
+        .iter()
+        .filter(|a| a.is_full())
+        .take(cap_full)
+        .map(|a| a.height)
+        .collect::<HashSet<_>>();
+
+    let mut tips_kept = 0;
+    for e in all.iter().filter(|a| !a.is_full()) {
+        if tips_kept >= cap_incr {
+            break;
+        }
+        // only chain tips count against `cap_incr`; intermediate
+        // incrementals are retained transitively by the walk below
+        if all.iter().any(|a| a.base == Some(e.height)) {
+            continue;
+        }
+        // keep the whole chain only if its root full survives
+        match root_full(e, all) {
+            Some(root) if keep.contains(&root) => {}
+            _ => continue,
+        }
+        let mut cur = e;
+        while let Some(b) = cur.base {
+            keep.insert(cur.height);
+            match all.iter().find(|a| a.height == b) {
+                Some(parent) => cur = parent,
+                None => break,
+            }
+        }
+        tips_kept += 1;
+    }
+
+    keep
+}
+
+/// follow an entry's base chain back to the full it roots at, if intact
+fn root_full(entry: &ArchiveEntry, archives: &[ArchiveEntry]) -> Option<u64> {
+    let mut cur = entry;
+    loop {
+        match cur.base {
+            None => return Some(cur.height),
+            Some(b) => cur = archives.iter().find(|a| a.height == b)?,
+        }
+    }
+}
+
+/// best-effort removal of an archive and its sidecar
+#[inline(always)]
+fn remove(entry: &ArchiveEntry) {
+    if let Some(path) = entry.path.to_str() {
+        info_omit!(cmd::exec_output(&format!("rm -f {p} {p}.sha256", p = path)));
+    }
+}
+
+/// confirm the archive still matches its recorded checksum
+pub(crate) fn verify(archive: &str) -> Result<()> {
+    let want = sha256(archive).c(d!())?;
+    let sidecar = format!("{}.sha256", archive);
+    let got = cmd::exec_output(&format!("cut -d' ' -f1 {}", &sidecar)).c(d!())?;
+    if want == got.trim() {
+        Ok(())
+    } else {
+        Err(eg!("checksum mismatch for {}", archive))
+    }
+}
+
+/// sha256 of a file, via `sha256sum(1)`
+#[inline(always)]
+fn sha256(path: &str) -> Result<String> {
+    let out = cmd::exec_output(&format!("sha256sum {}", path)).c(d!())?;
+    out.split_whitespace()
+        .next()
+        .map(|s| s.to_owned())
+        .c(d!("empty sha256sum output"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full(h: u64) -> ArchiveEntry {
+        ArchiveEntry {
+            height: h,
+            base: None,
+            fmt: ArchiveFormat::Zstd,
+            path: PathBuf::from(format!("/d/{}-full-hash.zst", h)),
+        }
+    }
+
+    fn incr(h: u64, base: u64) -> ArchiveEntry {
+        ArchiveEntry {
+            height: h,
+            base: Some(base),
+            fmt: ArchiveFormat::Zstd,
+            path: PathBuf::from(format!("/d/{}-incr{}-hash.zst", h, base)),
+        }
+    }
+
+    // mimic `sorted_archives`' 'DESC'-by-height ordering
+    fn desc(mut v: Vec<ArchiveEntry>) -> Vec<ArchiveEntry> {
+        v.sort_unstable_by(|a, b| b.height.cmp(&a.height));
+        v
+    }
+
+    #[test]
+    fn parse_discriminates_full_and_incremental() {
+        let f = ArchiveEntry::parse("/d", "100-full-abc.zst").unwrap();
+        assert_eq!(f.height, 100);
+        assert!(f.is_full());
+
+        let i = ArchiveEntry::parse("/d", "120-incr100-def.gz").unwrap();
+        assert_eq!(i.height, 120);
+        assert_eq!(i.base, Some(100));
+        assert!(!i.is_full());
+
+        assert!(ArchiveEntry::parse("/d", "not-an-archive.txt").is_none());
+    }
+
+    #[test]
+    fn resolve_chain_returns_full_then_incrementals() {
+        let archives =
+            desc(vec![full(100), incr(110, 100), incr(120, 110), full(200)]);
+        let heights = resolve_chain(&archives, 120)
+            .unwrap()
+            .iter()
+            .map(|e| e.height)
+            .collect::<Vec<_>>();
+        assert_eq!(heights, vec![100, 110, 120]);
+
+        // a missing mid-chain link is a hard error, not a silent skip
+        let broken = desc(vec![full(100), incr(120, 110)]);
+        assert!(resolve_chain(&broken, 120).is_err());
+    }
+
+    #[test]
+    fn retention_keeps_whole_chain_even_when_longer_than_cap() {
+        let archives = desc(vec![
+            full(100),
+            incr(110, 100),
+            incr(120, 110),
+            incr(130, 120),
+        ]);
+        // a single retained tip must drag its whole chain along, or
+        // `resolve_chain` for the newest height would break
+        let keep = retention(&archives, 1, 1);
+        for h in [100, 110, 120, 130] {
+            assert!(keep.contains(&h), "height {} should be kept", h);
+        }
+        let kept = archives
+            .iter()
+            .filter(|a| keep.contains(&a.height))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert!(resolve_chain(&kept, 130).is_ok());
+    }
+
+    #[test]
+    fn retention_drops_chains_whose_full_is_evicted() {
+        let archives =
+            desc(vec![full(100), incr(110, 100), incr(120, 110), full(200)]);
+        // only the newest full survives; the older chain goes with its full
+        let keep = retention(&archives, 1, 5);
+        assert!(keep.contains(&200));
+        assert!(!keep.contains(&100));
+        assert!(!keep.contains(&110));
+        assert!(!keep.contains(&120));
+    }
+}
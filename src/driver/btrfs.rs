@@ -0,0 +1,99 @@
+//!
+//! # The `btrfs` driver
+//!
+//! Snapshots are plain read-only subvolumes named `<volume>@<idx>`.
+//! Btrfs has no equivalent of the libzfs_core ioctls, so every operation
+//! shells out to `btrfs(8)`.
+//!
+
+use crate::{driver::progress::{self, Abort}, BtmCfg};
+use ruc::{cmd, *};
+
+/// make sure the target volume is a valid btrfs subvolume
+#[inline(always)]
+pub(crate) fn check(volume: &str) -> Result<()> {
+    let cmd = format!("btrfs subvolume show {}", volume);
+    cmd::exec_output(&cmd).c(d!()).map(|_| ())
+}
+
+/// create a read-only snapshot named `<volume>@<idx>`
+pub(crate) fn gen_snapshot(cfg: &BtmCfg, idx: u64) -> Result<()> {
+    let cmd = format!(
+        "rm -rf {vol}@{idx} 2>/dev/null; btrfs subvolume snapshot -r {vol} {vol}@{idx}",
+        vol = &cfg.volume,
+        idx = idx
+    );
+    cmd::exec_output(&cmd).c(d!()).map(|_| ())
+}
+
+/// rollback the volume to `idx`, or to the most recent snapshot if `None`
+pub(crate) fn rollback(
+    cfg: &BtmCfg,
+    idx: Option<u64>,
+    strict: bool,
+    abort: &Abort,
+) -> Result<()> {
+    progress::check(abort).c(d!())?;
+    let idx = if let Some(i) = idx {
+        if strict && !sorted_snapshots(cfg).c(d!())?.contains(&i) {
+            return Err(eg!("height {} does not exist", i));
+        }
+        i
+    } else {
+        sorted_snapshots(cfg)
+            .c(d!())?
+            .first()
+            .copied()
+            .c(d!("no snapshot to rollback to"))?
+    };
+
+    let cmd = format!(
+        "rm -rf {vol} || exit 1; btrfs subvolume snapshot {vol}@{idx} {vol}",
+        vol = &cfg.volume,
+        idx = idx
+    );
+    cmd::exec_output(&cmd).c(d!()).map(|_| ())
+}
+
+/// shell fragment that serializes `<volume>@<idx>` to stdout
+#[inline(always)]
+pub(crate) fn send_cmd(cfg: &BtmCfg, idx: u64) -> String {
+    format!("btrfs send {}@{}", &cfg.volume, idx)
+}
+
+/// shell fragment that serializes the delta from `<volume>@<prev>` to
+/// `<volume>@<cur>` to stdout
+#[inline(always)]
+pub(crate) fn send_incr_cmd(cfg: &BtmCfg, prev: u64, cur: u64) -> String {
+    format!(
+        "btrfs send -p {vol}@{prev} {vol}@{cur}",
+        vol = &cfg.volume
+    )
+}
+
+/// shell fragment that deserializes a stream into the parent of `volume`
+#[inline(always)]
+pub(crate) fn recv_cmd(cfg: &BtmCfg, _idx: u64) -> String {
+    let parent = cfg.volume.rsplit_once('/').map(|(p, _)| p).unwrap_or(".");
+    format!("btrfs receive {}", parent)
+}
+
+/// list snapshot heights of `volume` in 'DESC' order
+pub(crate) fn sorted_snapshots(cfg: &BtmCfg) -> Result<Vec<u64>> {
+    let cmd = format!("btrfs subvolume list -s {}", &cfg.volume);
+    let output = cmd::exec_output(&cmd).c(d!())?;
+
+    // the listed paths are matched by their last component, so match the
+    // volume's own last component rather than its full path
+    let name = cfg.volume.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    let prefix = format!("{}@", name);
+    let mut list = output
+        .split_whitespace()
+        .filter_map(|p| p.rsplit('/').next())
+        .filter_map(|p| p.strip_prefix(&prefix))
+        .filter_map(|h| h.parse::<u64>().ok())
+        .collect::<Vec<_>>();
+    list.sort_unstable_by(|a, b| b.cmp(a));
+
+    Ok(list)
+}
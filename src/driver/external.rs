@@ -0,0 +1,97 @@
+//!
+//! # The `external` driver
+//!
+//! A fully pluggable backend: every operation runs a user-supplied
+//! command template configured on [`BtmCfg`](crate::BtmCfg). The
+//! templates understand two placeholders, `{volume}` and `{height}`,
+//! which lets operators drive storage systems BTM has no native support
+//! for (LVM thin snapshots, cloud block-store snapshots, ...) without
+//! forking the crate.
+//!
+
+use crate::{
+    driver::progress::{self, Abort},
+    BtmCfg,
+};
+use ruc::{cmd, *};
+
+/// substitute `{volume}` and (optionally) `{height}` into a template
+#[inline(always)]
+fn render(tpl: &str, cfg: &BtmCfg, height: Option<u64>) -> String {
+    let mut s = tpl.replace("{volume}", &cfg.volume);
+    if let Some(h) = height {
+        s = s.replace("{height}", &h.to_string());
+    }
+    s
+}
+
+/// run `snapshot_cmd` for `<volume>` at height `idx`
+pub(crate) fn gen_snapshot(cfg: &BtmCfg, idx: u64) -> Result<()> {
+    let tpl = cfg
+        .snapshot_cmd
+        .as_deref()
+        .c(d!("`snapshot_cmd` is not configured"))?;
+    cmd::exec_output(&render(tpl, cfg, Some(idx)))
+        .c(d!())
+        .map(|_| ())
+}
+
+/// run `rollback_cmd`, targeting `idx` or the most recent snapshot
+pub(crate) fn rollback(
+    cfg: &BtmCfg,
+    idx: Option<u64>,
+    strict: bool,
+    abort: &Abort,
+) -> Result<()> {
+    progress::check(abort).c(d!())?;
+
+    let idx = if let Some(i) = idx {
+        if strict && !sorted_snapshots(cfg).c(d!())?.contains(&i) {
+            return Err(eg!("height {} does not exist", i));
+        }
+        i
+    } else {
+        sorted_snapshots(cfg)
+            .c(d!())?
+            .first()
+            .copied()
+            .c(d!("no snapshot to rollback to"))?
+    };
+
+    let tpl = cfg
+        .rollback_cmd
+        .as_deref()
+        .c(d!("`rollback_cmd` is not configured"))?;
+    cmd::exec_output(&render(tpl, cfg, Some(idx)))
+        .c(d!())
+        .map(|_| ())
+}
+
+/// run `list_cmd` and parse the heights it prints on stdout, in 'DESC'
+/// order
+pub(crate) fn sorted_snapshots(cfg: &BtmCfg) -> Result<Vec<u64>> {
+    let tpl = cfg
+        .list_cmd
+        .as_deref()
+        .c(d!("`list_cmd` is not configured"))?;
+    let output = cmd::exec_output(&render(tpl, cfg, None)).c(d!())?;
+
+    let mut list = output
+        .split_whitespace()
+        .filter_map(|h| h.parse::<u64>().ok())
+        .collect::<Vec<_>>();
+    list.sort_unstable_by(|a, b| b.cmp(a));
+
+    Ok(list)
+}
+
+/// run `destroy_cmd` for the snapshot at `height`
+pub(crate) fn destroy(cfg: &BtmCfg, height: u64) -> Result<()> {
+    let tpl = cfg
+        .destroy_cmd
+        .as_deref()
+        .c(d!("`destroy_cmd` is not configured"))?;
+    cmd::exec_output(&render(tpl, cfg, Some(height)))
+        .c(d!())
+        .map(|_| ())
+}
@@ -0,0 +1,13 @@
+//!
+//! # Snapshot drivers
+//!
+//! One submodule per supported backend; each exposes the same small
+//! surface (`check`, `gen_snapshot`, `rollback`, `sorted_snapshots`) so
+//! `BtmCfg` can dispatch on `SnapMode` without caring about the details.
+//!
+
+pub(crate) mod archive;
+pub(crate) mod btrfs;
+pub(crate) mod external;
+pub(crate) mod progress;
+pub(crate) mod zfs;
@@ -0,0 +1,46 @@
+//!
+//! # Cancellation and progress for long-running operations
+//!
+//! Exporting, importing or rolling back a large volume can run for
+//! minutes. An [`Abort`] flag lets a shutdown signal stop the work
+//! cleanly between pipeline stages instead of leaving a half-written
+//! target, and a [`Reporter`] lets the daemon observe how far a transfer
+//! has got.
+//!
+
+use ruc::*;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// A shared flag asking a long-running operation to stop early.
+pub type Abort = Arc<AtomicBool>;
+
+/// Create a fresh, un-aborted cancellation token.
+#[inline(always)]
+pub fn abort_token() -> Abort {
+    Arc::new(AtomicBool::new(false))
+}
+
+/// A snapshot of a transfer's progress.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Progress {
+    /// bytes written to the target so far
+    pub sent: u64,
+    /// estimated total bytes, e.g. from `zfs send -nv`
+    pub total: u64,
+}
+
+/// A sink the driver calls as a transfer advances.
+pub type Reporter<'a> = dyn Fn(Progress) + Send + Sync + 'a;
+
+/// Bail out with an error if the operation has been aborted.
+#[inline(always)]
+pub(crate) fn check(abort: &Abort) -> Result<()> {
+    if abort.load(Ordering::Relaxed) {
+        Err(eg!("operation aborted"))
+    } else {
+        Ok(())
+    }
+}
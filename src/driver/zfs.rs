@@ -0,0 +1,239 @@
+//!
+//! # The `zfs` driver
+//!
+//! Snapshots are created with the native libzfs_core ioctl interface
+//! (the surface exposed by the `zfs-core` crate: a `Zfs` handle opened
+//! against `/dev/zfs`). The `zfs(8)` CLI is used only as a fallback when
+//! the device node is unavailable, e.g. inside a restricted container.
+//!
+
+use crate::{driver::progress::{self, Abort}, BtmCfg};
+use ruc::{cmd, *};
+
+/// The character device the libzfs_core ioctls are issued against.
+const DEV_ZFS: &str = "/dev/zfs";
+
+/// Whether the native ioctl path can be used on this host.
+#[inline(always)]
+fn native_ready() -> bool {
+    std::path::Path::new(DEV_ZFS).exists()
+}
+
+/// make sure the target volume is a valid zfs dataset
+#[inline(always)]
+pub(crate) fn check(volume: &str) -> Result<()> {
+    let cmd = format!("zfs list {}", volume);
+    cmd::exec_output(&cmd).c(d!()).map(|_| ())
+}
+
+/// create a snapshot named `<volume>@<idx>`,
+/// destroying any stale snapshot at the same height first
+pub(crate) fn gen_snapshot(cfg: &BtmCfg, idx: u64) -> Result<()> {
+    let snap = format!("{}@{}", &cfg.volume, idx);
+    if native_ready() {
+        let zfs = native::Zfs::open().c(d!())?;
+        // a stale snapshot at this height is not an error
+        info_omit!(zfs.destroy(&snap));
+        zfs.snapshot(&snap).c(d!())
+    } else {
+        let cmd =
+            format!("zfs destroy {snap} 2>/dev/null; zfs snapshot {snap}", snap = snap);
+        cmd::exec_output(&cmd).c(d!()).map(|_| ())
+    }
+}
+
+/// destroy a batch of stale snapshots, holding a single `/dev/zfs`
+/// handle for the whole loop instead of re-opening it per height.
+///
+/// Cleanup is best-effort, but errors are classified rather than
+/// swallowed: a dataset that is *busy* is transient and worth flagging,
+/// whereas a *not-found* snapshot is simply already gone. Both are
+/// logged (not dropped) so the two cases are distinguishable in the
+/// daemon log.
+pub(crate) fn destroy_stale(cfg: &BtmCfg, heights: &[u64]) -> Result<()> {
+    let native = alt!(native_ready(), native::Zfs::open().ok(), None);
+    for &height in heights {
+        let snap = format!("{}@{}", &cfg.volume, height);
+        let res = match &native {
+            Some(zfs) => zfs.destroy(&snap).c(d!()),
+            None => cmd::exec_output(&format!("zfs destroy {}", snap))
+                .c(d!())
+                .map(|_| ()),
+        };
+        if let Err(e) = res {
+            let note = alt!(
+                e.to_string().to_lowercase().contains("busy"),
+                "dataset busy, deferring",
+                "not found, already gone?"
+            );
+            info_omit!(Result::<()>::Err(e).c(d!("destroy {}: {}", snap, note)));
+        }
+    }
+    Ok(())
+}
+
+/// rollback the volume to `idx`, or to the most recent snapshot if `None`
+pub(crate) fn rollback(
+    cfg: &BtmCfg,
+    idx: Option<u64>,
+    strict: bool,
+    abort: &Abort,
+) -> Result<()> {
+    progress::check(abort).c(d!())?;
+    let idx = if let Some(i) = idx {
+        if strict && !sorted_snapshots(cfg).c(d!())?.contains(&i) {
+            return Err(eg!("height {} does not exist", i));
+        }
+        i
+    } else {
+        sorted_snapshots(cfg)
+            .c(d!())?
+            .first()
+            .copied()
+            .c(d!("no snapshot to rollback to"))?
+    };
+
+    let snap = format!("{}@{}", &cfg.volume, idx);
+    if native_ready() {
+        native::Zfs::open().c(d!())?.rollback(&snap).c(d!())
+    } else {
+        let cmd = format!("zfs rollback -r {}", snap);
+        cmd::exec_output(&cmd).c(d!()).map(|_| ())
+    }
+}
+
+/// shell fragment that serializes `<volume>@<idx>` to stdout
+#[inline(always)]
+pub(crate) fn send_cmd(cfg: &BtmCfg, idx: u64) -> String {
+    format!("zfs send {}@{}", &cfg.volume, idx)
+}
+
+/// shell fragment that serializes the delta from `<volume>@<prev>` to
+/// `<volume>@<cur>` to stdout
+#[inline(always)]
+pub(crate) fn send_incr_cmd(cfg: &BtmCfg, prev: u64, cur: u64) -> String {
+    format!("zfs send -i {vol}@{prev} {vol}@{cur}", vol = &cfg.volume)
+}
+
+/// estimate the size of a (possibly incremental) send via `zfs send -nv`
+///
+/// Returns `0` when the estimate can't be parsed; callers treat that as
+/// "unknown total" for progress reporting.
+pub(crate) fn send_size(cfg: &BtmCfg, base: Option<u64>, idx: u64) -> u64 {
+    let dry = match base {
+        None => format!("zfs send -nv {}@{}", &cfg.volume, idx),
+        Some(b) => format!("zfs send -nv -i {vol}@{b} {vol}@{idx}", vol = &cfg.volume),
+    };
+    cmd::exec_output(&dry)
+        .ok()
+        .and_then(|out| parse_send_size(&out))
+        .unwrap_or(0)
+}
+
+/// pull the byte count out of a `total estimated size is 1.23G` line
+fn parse_send_size(output: &str) -> Option<u64> {
+    let tail = output.split("total estimated size is").nth(1)?;
+    parse_human_size(tail.split_whitespace().next()?)
+}
+
+/// parse a zfs-style human size (`512`, `1.50K`, `2G`) into bytes
+fn parse_human_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, scale) = match s.chars().last()? {
+        c @ ('K' | 'M' | 'G' | 'T' | 'P') => {
+            let scale = match c {
+                'K' => 1u64 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => 1 << 50,
+            };
+            (&s[..s.len() - 1], scale)
+        }
+        _ => (s, 1),
+    };
+    num.parse::<f64>().ok().map(|n| (n * scale as f64) as u64)
+}
+
+/// shell fragment that deserializes a stream back into `<volume>@<idx>`
+#[inline(always)]
+pub(crate) fn recv_cmd(cfg: &BtmCfg, idx: u64) -> String {
+    format!("zfs receive -F {}@{}", &cfg.volume, idx)
+}
+
+/// list snapshot heights of `volume` in 'DESC' order
+pub(crate) fn sorted_snapshots(cfg: &BtmCfg) -> Result<Vec<u64>> {
+    let cmd = format!(
+        "zfs list -H -t snapshot -o name -s name {}",
+        &cfg.volume
+    );
+    let output = cmd::exec_output(&cmd).c(d!())?;
+
+    let prefix = format!("{}@", &cfg.volume);
+    let mut list = output
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix(&prefix))
+        .filter_map(|h| h.parse::<u64>().ok())
+        .collect::<Vec<_>>();
+    list.sort_unstable_by(|a, b| b.cmp(a));
+
+    Ok(list)
+}
+
+/// Thin binding over the libzfs_core ioctl interface.
+///
+/// The whole point is to replace a `fork`-per-operation with a single
+/// `/dev/zfs` handle that returns typed errnos (dataset-busy vs.
+/// not-found) instead of opaque CLI text.
+mod native {
+    use ruc::*;
+
+    /// An open handle against `/dev/zfs`.
+    pub(super) struct Zfs(zfs_core::Zfs);
+
+    impl Zfs {
+        /// open a handle, mapping the raw errno into `ruc`
+        #[inline(always)]
+        pub(super) fn open() -> Result<Self> {
+            zfs_core::Zfs::init().map(Zfs).c(d!())
+        }
+
+        /// `lzc_snapshot`
+        #[inline(always)]
+        pub(super) fn snapshot(&self, name: &str) -> Result<()> {
+            self.0.snapshot(std::iter::once(name)).c(d!())
+        }
+
+        /// `lzc_destroy` (a snapshot is a dataset too)
+        #[inline(always)]
+        pub(super) fn destroy(&self, name: &str) -> Result<()> {
+            self.0.destroy(std::iter::once(name)).c(d!())
+        }
+
+        /// `lzc_rollback`
+        #[inline(always)]
+        pub(super) fn rollback(&self, name: &str) -> Result<()> {
+            self.0.rollback(name).c(d!())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_size_handles_scales_and_garbage() {
+        assert_eq!(parse_human_size("512"), Some(512));
+        assert_eq!(parse_human_size("1.50K"), Some(1536));
+        assert_eq!(parse_human_size("2G"), Some(2 << 30));
+        assert_eq!(parse_human_size("bogus"), None);
+    }
+
+    #[test]
+    fn send_size_pulls_the_estimate_line() {
+        let out = "full\ttank/data@1\ntotal estimated size is 1.23G\n";
+        assert_eq!(parse_send_size(out), Some((1.23 * (1u64 << 30) as f64) as u64));
+        assert_eq!(parse_send_size("nothing to see here"), None);
+    }
+}
@@ -13,12 +13,15 @@
 mod api;
 mod driver;
 
-pub use api::server::run_daemon;
+pub use api::server::{run_daemon, run_daemon_cancellable};
 
 use clap::Parser;
-use driver::{btrfs, external, zfs};
+use driver::{archive, btrfs, external, zfs};
 use ruc::{cmd, *};
-use std::{fmt, result::Result as StdResult, str::FromStr};
+use std::{fmt, path::PathBuf, result::Result as StdResult, str::FromStr};
+
+pub use driver::archive::{ArchiveEntry, ArchiveFormat};
+pub use driver::progress::{abort_token, Abort, Progress, Reporter};
 
 /// Maximum number of snapshots that can be kept
 pub const CAP_MAX: u64 = 4096;
@@ -52,6 +55,39 @@ pub struct BtmCfg {
     /// a data volume containing both ledger data and tendermint data
     #[clap(short = 'p', long, default_value_t = String::from("zfs/data"))]
     pub volume: String,
+    /// how many snapshots between full archive exports; the rest are
+    /// exported as incrementals chained onto the previous archive
+    #[clap(long, default_value_t = 10)]
+    pub full_itv: u64,
+    /// how many full archives to keep after a `clean_archives`
+    #[clap(long, default_value_t = 4)]
+    pub cap_full: usize,
+    /// how many incremental chain *tips* to keep after a `clean_archives`
+    ///
+    /// Retention works by whole chains, not by individual archives: each
+    /// kept tip drags its entire base chain back to the full along with
+    /// it (so `restore` never hits a broken chain). The number of
+    /// incremental archives actually retained is therefore this many
+    /// chains, which can be several times this value in archives.
+    #[clap(long, default_value_t = 100)]
+    pub cap_incremental: usize,
+    /// treat a failed snapshot as fatal: a validator running for hours
+    /// without a complete snapshot is worse than one that crashes
+    #[clap(long)]
+    pub strict_snapshot: bool,
+    /// `External` mode: command to create a snapshot; `{volume}` and
+    /// `{height}` are substituted
+    #[clap(long)]
+    pub snapshot_cmd: Option<String>,
+    /// `External` mode: command to rollback to `{height}`
+    #[clap(long)]
+    pub rollback_cmd: Option<String>,
+    /// `External` mode: command that prints existing heights on stdout
+    #[clap(long)]
+    pub list_cmd: Option<String>,
+    /// `External` mode: command to destroy the snapshot at `{height}`
+    #[clap(long)]
+    pub destroy_cmd: Option<String>,
 }
 
 impl Default for BtmCfg {
@@ -64,6 +100,14 @@ impl Default for BtmCfg {
             mode: SnapMode::Zfs,
             algo: SnapAlgo::Fair,
             volume: "zfs/data".to_owned(),
+            full_itv: 10,
+            cap_full: 4,
+            cap_incremental: 100,
+            strict_snapshot: false,
+            snapshot_cmd: None,
+            rollback_cmd: None,
+            list_cmd: None,
+            destroy_cmd: None,
         }
     }
 }
@@ -107,10 +151,22 @@ impl BtmCfg {
     /// rollback the state of blockchain to a specificed height
     #[inline(always)]
     pub fn rollback(&self, idx: Option<u64>, strict: bool) -> Result<()> {
+        self.rollback_cancellable(idx, strict, &abort_token()).c(d!())
+    }
+
+    /// like [`rollback`](Self::rollback) but checks `abort` before the
+    /// rollback starts; a rollback already running is not interrupted
+    #[inline(always)]
+    pub fn rollback_cancellable(
+        &self,
+        idx: Option<u64>,
+        strict: bool,
+        abort: &Abort,
+    ) -> Result<()> {
         match self.mode {
-            SnapMode::Zfs => zfs::rollback(self, idx, strict).c(d!()),
-            SnapMode::Btrfs => btrfs::rollback(self, idx, strict).c(d!()),
-            SnapMode::External => Err(eg!("please use `btm` tool in `External` mode")),
+            SnapMode::Zfs => zfs::rollback(self, idx, strict, abort).c(d!()),
+            SnapMode::Btrfs => btrfs::rollback(self, idx, strict, abort).c(d!()),
+            SnapMode::External => external::rollback(self, idx, strict, abort).c(d!()),
         }
     }
 
@@ -120,6 +176,128 @@ impl BtmCfg {
         match self.mode {
             SnapMode::Zfs => zfs::sorted_snapshots(self).c(d!()),
             SnapMode::Btrfs => btrfs::sorted_snapshots(self).c(d!()),
+            SnapMode::External => external::sorted_snapshots(self).c(d!()),
+        }
+    }
+
+    /// Export the snapshot at `idx` to a portable compressed archive
+    /// under `out_dir`, for off-host backup.
+    ///
+    /// A *full* archive is emitted every `full_itv` snapshots, counted as
+    /// `idx / itv` (and whenever no full archive yet exists, or when
+    /// `full_itv` is `0`); otherwise a cheaper *incremental*
+    /// archive carrying only the delta from the most recent archive is
+    /// written. Either way the archive is named with its height and a
+    /// sha256 content hash and dropped alongside a `.sha256` sidecar so
+    /// an operator can verify its integrity before trusting it for
+    /// catchup.
+    pub fn export(&self, idx: u64, fmt: ArchiveFormat, out_dir: &str) -> Result<PathBuf> {
+        self.export_cancellable(idx, fmt, out_dir, &abort_token(), &|_| {})
+            .c(d!())
+    }
+
+    /// like [`export`](Self::export) but abortable via `abort` and
+    /// reporting transfer progress through `report`
+    pub fn export_cancellable(
+        &self,
+        idx: u64,
+        fmt: ArchiveFormat,
+        out_dir: &str,
+        abort: &Abort,
+        report: &Reporter,
+    ) -> Result<PathBuf> {
+        let existing = self.get_sorted_archives(out_dir).c(d!())?;
+
+        // `full_itv` counts *snapshots* between fulls, not raw heights;
+        // snapshots land every `itv` blocks, so key the cadence on the
+        // snapshot ordinal `idx / itv`. Otherwise, with the default
+        // `itv == full_itv == 10`, every exported height is a multiple of
+        // `full_itv` and the incremental path would never run.
+        let itv = alt!(0 == self.itv, 1, self.itv);
+        let full = self.full_itv == 0
+            || 0 == (idx / itv) % self.full_itv
+            || !existing.iter().any(|a| a.is_full());
+
+        let (send, base) = if full {
+            (self.send_cmd(idx).c(d!())?, None)
+        } else {
+            let prev = existing
+                .first()
+                .map(|a| a.height)
+                .c(d!("no base archive for an incremental send"))?;
+            (self.send_incr_cmd(prev, idx).c(d!())?, Some(prev))
+        };
+
+        let total = match self.mode {
+            SnapMode::Zfs => zfs::send_size(self, base, idx),
+            _ => 0,
+        };
+
+        archive::export(&send, total, idx, base, fmt, out_dir, abort, report).c(d!())
+    }
+
+    /// Restore the volume to `idx` from archives under `out_dir`.
+    ///
+    /// Locates the most recent full archive at-or-before `idx` and
+    /// replays the incremental chain up to it; errors clearly if the
+    /// chain is broken.
+    pub fn restore(&self, idx: u64, out_dir: &str) -> Result<()> {
+        self.restore_cancellable(idx, out_dir, &abort_token()).c(d!())
+    }
+
+    /// like [`restore`](Self::restore) but abortable via `abort` before
+    /// each archive in the chain is received
+    pub fn restore_cancellable(
+        &self,
+        idx: u64,
+        out_dir: &str,
+        abort: &Abort,
+    ) -> Result<()> {
+        let archives = self.get_sorted_archives(out_dir).c(d!())?;
+        let chain = archive::resolve_chain(&archives, idx).c(d!())?;
+        for entry in chain.iter() {
+            let recv = self.recv_cmd(entry.height).c(d!())?;
+            archive::import(&recv, entry, abort).c(d!())?;
+        }
+        Ok(())
+    }
+
+    /// List archives under `out_dir` in 'DESC' order of height;
+    /// [`ArchiveEntry::is_full`] reports which heights are full bases.
+    #[inline(always)]
+    pub fn get_sorted_archives(&self, out_dir: &str) -> Result<Vec<ArchiveEntry>> {
+        archive::sorted_archives(out_dir).c(d!())
+    }
+
+    /// Purge archives under `out_dir` down to `cap_full`/`cap_incremental`.
+    #[inline(always)]
+    pub fn clean_archives(&self, out_dir: &str) -> Result<()> {
+        archive::purge(out_dir, self.cap_full, self.cap_incremental).c(d!())
+    }
+
+    #[inline(always)]
+    fn send_cmd(&self, idx: u64) -> Result<String> {
+        match self.mode {
+            SnapMode::Zfs => Ok(zfs::send_cmd(self, idx)),
+            SnapMode::Btrfs => Ok(btrfs::send_cmd(self, idx)),
+            SnapMode::External => Err(eg!("please use `btm` tool in `External` mode")),
+        }
+    }
+
+    #[inline(always)]
+    fn send_incr_cmd(&self, prev: u64, cur: u64) -> Result<String> {
+        match self.mode {
+            SnapMode::Zfs => Ok(zfs::send_incr_cmd(self, prev, cur)),
+            SnapMode::Btrfs => Ok(btrfs::send_incr_cmd(self, prev, cur)),
+            SnapMode::External => Err(eg!("please use `btm` tool in `External` mode")),
+        }
+    }
+
+    #[inline(always)]
+    fn recv_cmd(&self, idx: u64) -> Result<String> {
+        match self.mode {
+            SnapMode::Zfs => Ok(zfs::recv_cmd(self, idx)),
+            SnapMode::Btrfs => Ok(btrfs::recv_cmd(self, idx)),
             SnapMode::External => Err(eg!("please use `btm` tool in `External` mode")),
         }
     }
@@ -151,21 +329,34 @@ impl BtmCfg {
 
     /// Clean all existing snapshots.
     pub fn clean_snapshots(&self) -> Result<()> {
-        self.get_sorted_snapshots().c(d!()).map(|list| {
-            list.into_iter()
-                .skip(self.cap_clean_kept)
-                .rev()
-                .for_each(|height| {
-                    let cmd = match self.mode {
-                        SnapMode::Btrfs => {
-                            format!("btrfs subvolume delete {}@{}", &self.volume, height)
-                        }
-                        SnapMode::Zfs => format!("zfs destroy {}@{}", &self.volume, height),
-                        _ => pnk!(Err(eg!("Unsupported deriver"))),
-                    };
+        let stale = self
+            .get_sorted_snapshots()
+            .c(d!())?
+            .into_iter()
+            .skip(self.cap_clean_kept)
+            .rev()
+            .collect::<Vec<_>>();
+
+        match self.mode {
+            // route the whole batch through one `/dev/zfs` handle so the
+            // native typed errnos (busy vs. not-found) are inspected
+            // rather than re-opened and swallowed per height
+            SnapMode::Zfs => zfs::destroy_stale(self, &stale).c(d!()),
+            SnapMode::Btrfs => {
+                stale.into_iter().for_each(|height| {
+                    let cmd =
+                        format!("btrfs subvolume delete {}@{}", &self.volume, height);
                     info_omit!(cmd::exec_output(&cmd));
                 });
-        })
+                Ok(())
+            }
+            SnapMode::External => {
+                stale.into_iter().for_each(|height| {
+                    info_omit!(external::destroy(self, height));
+                });
+                Ok(())
+            }
+        }
     }
 }
 
@@ -210,8 +401,9 @@ pub enum SnapMode {
     /// available on most Linux distributions,
     /// but its user experience is worse than zfs
     Btrfs,
-    /// TODO: unimplemented!
-    /// rely on an external independent process
+    /// rely on user-supplied command templates (`snapshot_cmd`,
+    /// `rollback_cmd`, `list_cmd`, `destroy_cmd`), for storage systems
+    /// BTM has no native driver for
     External,
 }
 